@@ -3,11 +3,15 @@
 // All rights reserved. Distributed under the terms of the MIT License.
 //
 
-use std::collections::VecDeque;
-use std::sync::{Arc, Mutex};
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::sync::atomic::AtomicU64;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Instant;
 
 use ::app::{B_READY_TO_RUN, Handler, Message, Messenger};
-use ::app::looper::Looper;
+use ::app::inspector::Inspector;
+use ::app::looper::{CancellationToken, HandlerSlot, Looper, RequestId, WorkerPool, BLOCKING_WORKER_POOL_SIZE};
+use ::app::relay::Relay;
 use ::kernel::ports::Port;
 use ::support::Result;
 
@@ -23,24 +27,36 @@ impl<A> Application<A> where A: ApplicationHooks + Send + 'static {
 		// Set up some defaults
 		let port = Port::create("application", LOOPER_PORT_DEFAULT_CAPACITY).unwrap();
 		let state = Arc::new(Mutex::new(initial_state));
-		let default_looper_state = Box::new(ApplicationLooperState{});
+		let default_looper_state: Box<dyn Handler<A> + Send> = Box::new(ApplicationLooperState{});
+		let next_runner_token = Arc::new(AtomicU64::new(0));
 		let context = Context {
 			looper_messenger: Messenger::from_port(&port).unwrap(),
 			application_messenger: Messenger::from_port(&port).unwrap(),
-			application_state: state.clone()
+			application_state: state.clone(),
+			relay: None,
+			inspector: Arc::new(Inspector::new()),
+			next_request_id: Arc::new(AtomicU64::new(0)),
+			next_runner_token: next_runner_token.clone(),
+			pending_requests: Arc::new(Mutex::new(HashMap::new())),
+			current_request_id: None,
+			cancellation: CancellationToken::new()
 		};
 		let mut inner_looper = Looper {
 			name: String::from("application"),
 			port: port,
 			message_queue: VecDeque::new(),
-//			handlers: Vec::new(),
+			handlers: HashMap::new(),
+			next_handler_token: 0,
 			context: context,
-			state: default_looper_state,
-			terminating: false
+			state: Arc::new(HandlerSlot::new(default_looper_state)),
+			terminating: false,
+			scheduled: BinaryHeap::new(),
+			next_runner_token: next_runner_token,
+			workers: Arc::new(WorkerPool::new(BLOCKING_WORKER_POOL_SIZE))
 		};
 		
 		// Add the READY_TO_RUN message to the queue
-		inner_looper.message_queue.push_back(Message::new(B_READY_TO_RUN));
+		inner_looper.message_queue.push_back((Message::new(B_READY_TO_RUN), Instant::now()));
 		
 		Self {
 			state: state,
@@ -51,19 +67,31 @@ impl<A> Application<A> where A: ApplicationHooks + Send + 'static {
 	pub fn create_looper(&mut self, name: &str, initial_state: Box<dyn Handler<A> + Send>) -> Looper<A>
 	{
 		let port = Port::create(name, LOOPER_PORT_DEFAULT_CAPACITY).unwrap();
+		let next_runner_token = Arc::new(AtomicU64::new(0));
 		let context = Context {
 			looper_messenger: Messenger::from_port(&port).unwrap(),
 			application_messenger: self.inner_looper.get_messenger(),
-			application_state: self.state.clone()
+			application_state: self.state.clone(),
+			relay: self.inner_looper.context.relay.clone(),
+			inspector: self.inner_looper.context.inspector.clone(),
+			next_request_id: Arc::new(AtomicU64::new(0)),
+			next_runner_token: next_runner_token.clone(),
+			pending_requests: Arc::new(Mutex::new(HashMap::new())),
+			current_request_id: None,
+			cancellation: CancellationToken::new()
 		};
 		Looper {
 			name: String::from(name),
 			port: port,
 			message_queue: VecDeque::new(),
-//			handlers: vec![initial_handler],
+			handlers: HashMap::new(),
+			next_handler_token: 0,
 			context: context,
-			state: initial_state,
-			terminating: false
+			state: Arc::new(HandlerSlot::new(initial_state)),
+			terminating: false,
+			scheduled: BinaryHeap::new(),
+			next_runner_token: next_runner_token,
+			workers: Arc::new(WorkerPool::new(BLOCKING_WORKER_POOL_SIZE))
 		}
 	}
 	
@@ -76,12 +104,76 @@ impl<A> Application<A> where A: ApplicationHooks + Send + 'static {
 	pub fn get_messenger(&self) -> Messenger {
 		self.inner_looper.get_messenger()
 	}
+
+	/// Attach `relay` to this application's main looper, exposing its port to
+	/// remote peers and making the relay available through `Context::relay`
+	/// for any looper created afterwards.
+	pub fn attach_relay(&mut self, relay: Relay) {
+		let relay = Arc::new(relay);
+		relay.expose(self.inner_looper.port.clone());
+		self.inner_looper.context.relay = Some(relay);
+	}
+
+	/// Register `observer` to be notified of traffic matching `filter` across
+	/// every looper of this application, including ones created afterwards.
+	pub fn observe(&mut self, filter: ::app::inspector::TraceFilter, observer: Box<dyn ::app::inspector::TraceObserver + Send>) {
+		self.inner_looper.context.inspector.subscribe(filter, observer);
+	}
 }
 
 pub struct Context<A> where A: Send {
 	pub looper_messenger: Messenger,
 	pub application_messenger: Messenger,
-	pub application_state: Arc<Mutex<A>>
+	pub application_state: Arc<Mutex<A>>,
+	pub(crate) relay: Option<Arc<Relay>>,
+	pub(crate) inspector: Arc<Inspector>,
+	pub(crate) next_request_id: Arc<AtomicU64>,
+	pub(crate) next_runner_token: Arc<AtomicU64>,
+	pub(crate) pending_requests: Arc<Mutex<HashMap<RequestId, mpsc::Sender<Message>>>>,
+	pub(crate) current_request_id: Option<RequestId>,
+	pub(crate) cancellation: CancellationToken,
+}
+
+// Each dispatch gets its own `Context` (see `Looper::dispatch_to_handler`), so
+// a blocking dispatch on the worker pool doesn't race another dispatch over
+// which request `Context::reply` should echo. Implemented by hand, since
+// `#[derive(Clone)]` would wrongly require `A: Clone` even though every field
+// here is cheap to clone regardless of `A`.
+impl<A> Clone for Context<A> where A: Send {
+	fn clone(&self) -> Self {
+		Context {
+			looper_messenger: self.looper_messenger.clone(),
+			application_messenger: self.application_messenger.clone(),
+			application_state: self.application_state.clone(),
+			relay: self.relay.clone(),
+			inspector: self.inspector.clone(),
+			next_request_id: self.next_request_id.clone(),
+			next_runner_token: self.next_runner_token.clone(),
+			pending_requests: self.pending_requests.clone(),
+			current_request_id: self.current_request_id,
+			cancellation: self.cancellation.clone(),
+		}
+	}
+}
+
+impl<A> Context<A> where A: Send {
+	/// The relay that bridges this context's looper to remote peers, if one
+	/// has been attached via `Application::attach_relay`.
+	pub fn relay(&self) -> Option<&Arc<Relay>> {
+		self.relay.as_ref()
+	}
+
+	/// The message-trace inspector shared by every looper of this application.
+	pub fn inspector(&self) -> &Arc<Inspector> {
+		&self.inspector
+	}
+
+	/// The cancellation token carried by this context's looper, tripped when
+	/// `QUIT`/`B_QUIT_REQUESTED` arrives so an in-flight blocking handler can
+	/// check [`CancellationToken::is_cancelled`] and abort early.
+	pub fn cancellation(&self) -> &CancellationToken {
+		&self.cancellation
+	}
 }
 
 pub trait ApplicationHooks {
@@ -198,4 +290,237 @@ mod tests {
 
 		application.run();
 	}
+
+	#[test]
+	fn blocking_handler_does_not_stall_dispatch_to_other_handlers() {
+		use std::sync::mpsc;
+		use std::time::Duration;
+
+		const BLOCK: u32 = haiku_constant!('B','L','K','0');
+		const PING: u32 = haiku_constant!('P','I','N','G');
+
+		struct BlockingHandler {
+			entered: mpsc::Sender<()>,
+			release: mpsc::Receiver<()>,
+		}
+
+		impl Handler<ApplicationState> for BlockingHandler {
+			fn message_received(&mut self, _context: &Context<ApplicationState>, _message: &Message) {
+				self.entered.send(()).unwrap();
+				self.release.recv_timeout(Duration::from_secs(5)).unwrap();
+			}
+
+			fn is_blocking(&self, _message: &Message) -> bool {
+				true
+			}
+		}
+
+		struct PingHandler {
+			pinged: mpsc::Sender<()>,
+		}
+
+		impl Handler<ApplicationState> for PingHandler {
+			fn message_received(&mut self, _context: &Context<ApplicationState>, _message: &Message) {
+				self.pinged.send(()).unwrap();
+			}
+		}
+
+		let (entered_tx, entered_rx) = mpsc::channel();
+		let (release_tx, release_rx) = mpsc::channel();
+		let (pinged_tx, pinged_rx) = mpsc::channel();
+
+		let application_state = ApplicationState { total_count: 0 };
+		let mut application = Application::new(application_state);
+		let mut looper = application.create_looper("blocking", Box::new(BlockingHandler { entered: entered_tx, release: release_rx }));
+		let looper_messenger = looper.get_messenger();
+		let ping_token = looper.add_handler(Box::new(PingHandler { pinged: pinged_tx }));
+		let ping_messenger = looper.handler_messenger(ping_token);
+		assert!(looper.run().is_ok());
+
+		let app_messenger = application.get_messenger();
+
+		// Two back-to-back messages to the same (blocking) default-state
+		// handler: the first runs on the worker pool and blocks on `release`;
+		// the second must not make the looper thread contend for the same
+		// handler lock while deciding how to dispatch it.
+		looper_messenger.send_and_ask_reply(Message::new(BLOCK), &app_messenger);
+		entered_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+		looper_messenger.send_and_ask_reply(Message::new(BLOCK), &app_messenger);
+
+		// A message to an unrelated handler on the same looper should still
+		// be dispatched promptly, proving the looper thread wasn't stuck
+		// deciding the second BLOCK message's fate.
+		ping_messenger.send_and_ask_reply(Message::new(PING), &app_messenger);
+		pinged_rx.recv_timeout(Duration::from_secs(1)).expect("looper thread stalled dispatching to an unrelated handler");
+
+		release_tx.send(()).unwrap();
+		release_tx.send(()).unwrap();
+	}
+
+	#[test]
+	fn a_panicking_blocking_handler_does_not_poison_the_next_dispatch() {
+		use std::sync::mpsc;
+		use std::time::Duration;
+
+		const BOOM: u32 = haiku_constant!('B','O','O','M');
+		const PING: u32 = haiku_constant!('P','I','N','G');
+
+		struct FlakyHandler {
+			pinged: mpsc::Sender<()>,
+		}
+
+		impl Handler<ApplicationState> for FlakyHandler {
+			fn message_received(&mut self, _context: &Context<ApplicationState>, message: &Message) {
+				if message.what() == BOOM {
+					panic!("boom");
+				}
+				self.pinged.send(()).unwrap();
+			}
+
+			fn is_blocking(&self, message: &Message) -> bool {
+				message.what() == BOOM
+			}
+		}
+
+		let (pinged_tx, pinged_rx) = mpsc::channel();
+		let application_state = ApplicationState { total_count: 0 };
+		let mut application = Application::new(application_state);
+		let looper = application.create_looper("flaky", Box::new(FlakyHandler { pinged: pinged_tx }));
+		let looper_messenger = looper.get_messenger();
+		assert!(looper.run().is_ok());
+
+		let app_messenger = application.get_messenger();
+
+		// BOOM is blocking, so it runs (and panics) on the worker pool rather
+		// than on the looper thread itself.
+		looper_messenger.send_and_ask_reply(Message::new(BOOM), &app_messenger);
+
+		// A later, plain PING to the same handler must still go through —
+		// whichever thread ends up locking the handler for it, the panic
+		// above must not have left its Mutex poisoned.
+		looper_messenger.send_and_ask_reply(Message::new(PING), &app_messenger);
+		pinged_rx.recv_timeout(Duration::from_secs(5)).expect("handler's lock was poisoned by an earlier worker-pool panic");
+	}
+
+	#[test]
+	fn message_runner_fires_its_count_then_stops_and_can_be_cancelled() {
+		use std::sync::mpsc;
+		use std::time::Duration;
+
+		const FIRE: u32 = haiku_constant!('F','I','R','E');
+
+		struct FireCounter {
+			fired: mpsc::Sender<()>,
+		}
+
+		impl Handler<ApplicationState> for FireCounter {
+			fn message_received(&mut self, _context: &Context<ApplicationState>, message: &Message) {
+				if message.what() == FIRE {
+					self.fired.send(()).unwrap();
+				}
+			}
+		}
+
+		let (fired_tx, fired_rx) = mpsc::channel();
+		let application_state = ApplicationState { total_count: 0 };
+		let mut application = Application::new(application_state);
+		let looper = application.create_looper("timers", Box::new(FireCounter { fired: fired_tx }));
+		let target = looper.get_messenger();
+		let context = looper.context.clone();
+		assert!(looper.run().is_ok());
+
+		// A runner limited to 3 repeats fires exactly that many times, then
+		// stops firing on its own.
+		let _runner = context.send_message_repeating(target.clone(), Message::new(FIRE), Duration::from_millis(10), Some(3)).unwrap();
+		for _ in 0..3 {
+			fired_rx.recv_timeout(Duration::from_secs(1)).expect("runner did not fire the expected number of times");
+		}
+		assert!(fired_rx.recv_timeout(Duration::from_millis(200)).is_err(), "runner fired more than its count");
+
+		// An unlimited runner keeps firing until explicitly cancelled.
+		let runner = context.send_message_repeating(target, Message::new(FIRE), Duration::from_millis(10), None).unwrap();
+		fired_rx.recv_timeout(Duration::from_secs(1)).expect("runner never fired");
+		runner.cancel().unwrap();
+		assert!(fired_rx.recv_timeout(Duration::from_millis(200)).is_err(), "runner fired again after being cancelled");
+	}
+
+	struct IdleHandler;
+
+	impl Handler<ApplicationState> for IdleHandler {
+		fn message_received(&mut self, _context: &Context<ApplicationState>, _message: &Message) {}
+	}
+
+	#[test]
+	fn send_for_reply_round_trips_a_value_between_two_loopers() {
+		use std::time::Duration;
+
+		const ASK: u32 = haiku_constant!('A','S','K','0');
+
+		struct Responder;
+
+		impl Handler<ApplicationState> for Responder {
+			fn message_received(&mut self, context: &Context<ApplicationState>, message: &Message) {
+				let mut answer = Message::new(ASK);
+				answer.add_data("value", &42u32);
+				let target = message.get_return_address().unwrap();
+				context.reply(&target, answer).unwrap();
+			}
+		}
+
+		let application_state = ApplicationState { total_count: 0 };
+		let mut application = Application::new(application_state);
+
+		let responder = application.create_looper("responder", Box::new(Responder{}));
+		let responder_messenger = responder.get_messenger();
+		assert!(responder.run().is_ok());
+
+		let asker = application.create_looper("asker", Box::new(IdleHandler{}));
+		let asker_context = asker.context.clone();
+		assert!(asker.run().is_ok());
+
+		let pending = asker_context.send_for_reply(responder_messenger, Message::new(ASK)).unwrap();
+		let reply = pending.recv_timeout(Duration::from_secs(1)).expect("no reply received");
+		assert_eq!(reply.find_data::<u32>("value", 0).unwrap(), 42);
+	}
+
+	#[test]
+	fn pending_reply_removes_itself_from_pending_requests_on_cancel_and_drop() {
+		const ASK: u32 = haiku_constant!('A','S','K','1');
+
+		let application_state = ApplicationState { total_count: 0 };
+		let mut application = Application::new(application_state);
+		let looper = application.create_looper("asker", Box::new(IdleHandler{}));
+		let context = looper.context.clone();
+		// Nothing ever replies to ASK here; we only care about pending_requests
+		// bookkeeping, not actually resolving the reply.
+		let never_replies = looper.get_messenger();
+		assert!(looper.run().is_ok());
+
+		let pending = context.send_for_reply(never_replies.clone(), Message::new(ASK)).unwrap();
+		assert_eq!(context.pending_requests.lock().unwrap().len(), 1);
+		pending.cancel();
+		assert!(context.pending_requests.lock().unwrap().is_empty(), "cancel() left a stale pending_requests entry");
+
+		let pending = context.send_for_reply(never_replies, Message::new(ASK)).unwrap();
+		assert_eq!(context.pending_requests.lock().unwrap().len(), 1);
+		drop(pending);
+		assert!(context.pending_requests.lock().unwrap().is_empty(), "dropping a PendingReply left a stale pending_requests entry");
+	}
+
+	#[test]
+	fn pending_reply_recv_timeout_times_out_when_nothing_replies() {
+		use std::time::Duration;
+
+		const ASK: u32 = haiku_constant!('A','S','K','2');
+
+		let application_state = ApplicationState { total_count: 0 };
+		let mut application = Application::new(application_state);
+		let looper = application.create_looper("asker", Box::new(IdleHandler{}));
+		let context = looper.context.clone();
+		let never_replies = looper.get_messenger();
+		assert!(looper.run().is_ok());
+
+		let pending = context.send_for_reply(never_replies, Message::new(ASK)).unwrap();
+		assert!(pending.recv_timeout(Duration::from_millis(100)).is_err());
+	}
 }
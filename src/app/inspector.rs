@@ -0,0 +1,222 @@
+//
+// Copyright 2019, Niels Sascha Reedijk <niels.reedijk@gmail.com>
+// All rights reserved. Distributed under the terms of the MIT License.
+//
+
+//! A structured, subscribable trace facility for messages moving through an
+//! `Application`'s loopers, replacing the ad-hoc `println!` debug lines that
+//! used to live in `Looper::looper_task`. An [`Inspector`] fans a
+//! [`TraceEvent`] out to whichever registered [`TraceObserver`]s match it,
+//! without the handler code itself knowing it is being watched.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use ::app::{HandlerToken, Message, Messenger};
+
+/// The point in a message's life that a [`TraceEvent`] was captured at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TracePoint {
+	/// The message was just read off a looper's port, before being queued.
+	Read,
+	/// The message is about to be dispatched to a handler (or the looper's
+	/// default state, if its target token doesn't resolve to one).
+	Dispatch,
+}
+
+/// A single observed message, as delivered to a [`TraceObserver`].
+#[derive(Debug, Clone)]
+pub struct TraceEvent {
+	pub point: TracePoint,
+	pub looper_name: String,
+	/// The message's `what` code, decoded back to its four-char form.
+	pub what: [u8; 4],
+	pub source: Option<Messenger>,
+	pub target_handler: Option<HandlerToken>,
+	pub queue_depth: usize,
+	pub read_at: Instant,
+	pub dispatched_at: Option<Instant>,
+	/// A flattened copy of the message body, present only if the observer's
+	/// [`TraceFilter`] asked to capture it.
+	pub body: Option<Message>,
+}
+
+impl TraceEvent {
+	/// How long the message waited between being read and being dispatched,
+	/// if it has reached that point yet.
+	pub fn latency(&self) -> Option<Duration> {
+		self.dispatched_at.map(|dispatched_at| dispatched_at.duration_since(self.read_at))
+	}
+}
+
+/// Narrows which messages a [`TraceObserver`] is told about.
+#[derive(Default, Clone)]
+pub struct TraceFilter {
+	pub what: Option<u32>,
+	pub target_handler: Option<HandlerToken>,
+	pub capture_body: bool,
+}
+
+impl TraceFilter {
+	fn matches(&self, what: u32, target_handler: Option<HandlerToken>) -> bool {
+		if let Some(filtered_what) = self.what {
+			if filtered_what != what {
+				return false;
+			}
+		}
+		if let Some(filtered_token) = self.target_handler {
+			if Some(filtered_token) != target_handler {
+				return false;
+			}
+		}
+		true
+	}
+}
+
+/// Receives a [`TraceEvent`] for every message that matches the
+/// [`TraceFilter`] it was registered with.
+pub trait TraceObserver {
+	fn on_trace_event(&mut self, event: &TraceEvent);
+}
+
+struct Subscription {
+	filter: TraceFilter,
+	observer: Box<dyn TraceObserver + Send>,
+}
+
+/// Owns the set of subscribed [`TraceObserver`]s for one `Application` and
+/// fans a [`TraceEvent`] out to whichever of them match, from every looper
+/// that shares this inspector through its `Context`.
+#[derive(Default)]
+pub struct Inspector {
+	subscriptions: Mutex<Vec<Subscription>>,
+}
+
+impl Inspector {
+	pub fn new() -> Self {
+		Inspector { subscriptions: Mutex::new(Vec::new()) }
+	}
+
+	/// Register `observer`, to be notified of events matching `filter`.
+	pub fn subscribe(&self, filter: TraceFilter, observer: Box<dyn TraceObserver + Send>) {
+		self.subscriptions.lock().unwrap().push(Subscription { filter, observer });
+	}
+
+	/// Whether any observer is currently subscribed; lets callers skip
+	/// building a `TraceEvent` entirely when nobody is listening.
+	pub fn has_observers(&self) -> bool {
+		!self.subscriptions.lock().unwrap().is_empty()
+	}
+
+	pub(crate) fn record(&self, point: TracePoint, looper_name: &str, message: &Message,
+		source: Option<Messenger>, target_handler: Option<HandlerToken>, queue_depth: usize,
+		read_at: Instant, dispatched_at: Option<Instant>)
+	{
+		let mut subscriptions = self.subscriptions.lock().unwrap();
+		if subscriptions.is_empty() {
+			return;
+		}
+		let what = message.what();
+		for subscription in subscriptions.iter_mut() {
+			if !subscription.filter.matches(what, target_handler) {
+				continue;
+			}
+			let event = TraceEvent {
+				point,
+				looper_name: looper_name.to_string(),
+				what: four_char_code(what),
+				source: source.clone(),
+				target_handler,
+				queue_depth,
+				read_at,
+				dispatched_at,
+				body: if subscription.filter.capture_body { Some(message.clone()) } else { None },
+			};
+			subscription.observer.on_trace_event(&event);
+		}
+	}
+}
+
+// Decode a `what` type code back into its four-char form, the inverse of the
+// `haiku_constant!` macro used to define codes like `B_QUIT_REQUESTED`.
+fn four_char_code(what: u32) -> [u8; 4] {
+	[(what >> 24) as u8, (what >> 16) as u8, (what >> 8) as u8, what as u8]
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::mpsc;
+
+	use ::app::{Application, ApplicationHooks, Handler};
+
+	struct NoopState;
+
+	impl ApplicationHooks for NoopState {
+		fn message_received(&mut self, _application_messenger: &Messenger, _message: &Message) {}
+	}
+
+	struct NoopHandler;
+
+	impl Handler<NoopState> for NoopHandler {
+		fn message_received(&mut self, _context: &::app::Context<NoopState>, _message: &Message) {}
+	}
+
+	struct RecordingObserver {
+		events: mpsc::Sender<TraceEvent>,
+	}
+
+	impl TraceObserver for RecordingObserver {
+		fn on_trace_event(&mut self, event: &TraceEvent) {
+			self.events.send(event.clone()).unwrap();
+		}
+	}
+
+	const PING: u32 = haiku_constant!('P','I','N','G');
+	const PONG: u32 = haiku_constant!('P','O','N','G');
+
+	#[test]
+	fn record_delivers_only_to_observers_whose_filter_matches() {
+		let mut application = Application::new(NoopState);
+		let mut looper = application.create_looper("traced", Box::new(NoopHandler{}));
+		let watched_token = looper.add_handler(Box::new(NoopHandler{}));
+		let unwatched_token = looper.add_handler(Box::new(NoopHandler{}));
+
+		let inspector = Inspector::new();
+		assert!(!inspector.has_observers());
+
+		let (tx, rx) = mpsc::channel();
+		inspector.subscribe(
+			TraceFilter { what: Some(PING), target_handler: Some(watched_token), capture_body: true },
+			Box::new(RecordingObserver { events: tx }),
+		);
+		assert!(inspector.has_observers());
+
+		let read_at = Instant::now();
+		let mut message = Message::new(PING);
+		message.add_data("value", &7u32);
+
+		// Wrong `what`: filtered out, no event delivered.
+		let wrong_what = Message::new(PONG);
+		inspector.record(TracePoint::Read, "traced", &wrong_what, None, Some(watched_token), 0, read_at, None);
+
+		// Wrong handler: filtered out, no event delivered.
+		inspector.record(TracePoint::Read, "traced", &message, None, Some(unwatched_token), 0, read_at, None);
+
+		// Matches both `what` and `target_handler`: delivered, with the body
+		// captured since the filter asked for it.
+		let dispatched_at = read_at + Duration::from_millis(5);
+		inspector.record(TracePoint::Dispatch, "traced", &message, None, Some(watched_token), 3, read_at, Some(dispatched_at));
+
+		let event = rx.recv_timeout(Duration::from_millis(100)).expect("matching event was not delivered");
+		assert!(rx.recv_timeout(Duration::from_millis(50)).is_err(), "a non-matching event was delivered too");
+
+		assert_eq!(event.point, TracePoint::Dispatch);
+		assert_eq!(event.what, four_char_code(PING));
+		assert_eq!(event.target_handler, Some(watched_token));
+		assert_eq!(event.queue_depth, 3);
+		assert_eq!(event.latency(), Some(Duration::from_millis(5)));
+		let body = event.body.expect("capture_body was set but the body was not captured");
+		assert_eq!(body.find_data::<u32>("value", 0).unwrap(), 7);
+	}
+}
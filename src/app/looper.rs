@@ -3,42 +3,327 @@
 // All rights reserved. Distributed under the terms of the MIT License.
 //
 
-use std::collections::VecDeque;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
 use std::marker::Send;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering as AtomicOrdering};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::bounded;
 
 use ::app::{Context, Message, Messenger, B_QUIT_REQUESTED, QUIT};
+use ::app::inspector::TracePoint;
 use ::kernel::ports::Port;
 use ::kernel::INFINITE_TIMEOUT;
 use ::support::{ErrorKind, Flattenable, HaikuError, Result};
 
+// The number of threads kept around to run messages a `Handler` declares
+// "blocking" via `Handler::is_blocking`, so that one slow handler no longer
+// stalls the rest of the looper's port.
+pub(crate) const BLOCKING_WORKER_POOL_SIZE: usize = 4;
+
 pub trait Handler<A> where A: Send + 'static {
 	fn message_received(&mut self, context: &Context<A>, message: &Message);
+
+	/// Whether `message` should run on the looper's blocking worker pool
+	/// instead of inline on the looper thread, which keeps the looper free
+	/// to keep draining its port while this call is in flight.
+	///
+	/// Defaults to `false`: handlers are inline unless they opt in.
+	fn is_blocking(&self, _message: &Message) -> bool {
+		false
+	}
+}
+
+/// A cooperative cancellation signal carried in every `Context`, so a
+/// blocking handler can be asked to abort, e.g. when `QUIT`/`B_QUIT_REQUESTED`
+/// arrives at its looper. Cancellation is advisory: a handler has to check
+/// [`CancellationToken::is_cancelled`] itself to actually stop early.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+	pub(crate) fn new() -> Self {
+		CancellationToken(Arc::new(AtomicBool::new(false)))
+	}
+
+	pub fn cancel(&self) {
+		self.0.store(true, AtomicOrdering::Relaxed);
+	}
+
+	pub fn is_cancelled(&self) -> bool {
+		self.0.load(AtomicOrdering::Relaxed)
+	}
+}
+
+// A registered `Handler` plus a count of blocking dispatches currently in
+// flight for it on the worker pool. The looper consults `blocking_in_flight`
+// first, and only takes the handler's own lock (to ask `is_blocking`) when
+// no blocking dispatch is in flight, so it never contends with a worker that is mid-call inside
+// `Handler::message_received` (which can legitimately hold the lock for a
+// long time; that is the whole point of calling it "blocking").
+pub(crate) struct HandlerSlot<A> where A: Send + 'static {
+	handler: Mutex<Box<dyn Handler<A> + Send>>,
+	blocking_in_flight: AtomicU32,
+}
+
+impl<A> HandlerSlot<A> where A: Send + 'static {
+	pub(crate) fn new(handler: Box<dyn Handler<A> + Send>) -> Self {
+		HandlerSlot { handler: Mutex::new(handler), blocking_in_flight: AtomicU32::new(0) }
+	}
+}
+
+// One blocking dispatch handed off to the worker pool: the handler slot to
+// run it on (shared with the looper, so the looper can keep routing other
+// messages to it while this call is in flight), the per-message `Context`
+// (whose cancellation token the looper trips on `QUIT`/`B_QUIT_REQUESTED`),
+// and the message itself.
+struct Job<A> where A: Send + 'static {
+	handler: Arc<HandlerSlot<A>>,
+	context: Context<A>,
+	message: Message,
+}
+
+pub(crate) struct WorkerPool<A> where A: Send + 'static {
+	sender: crossbeam_channel::Sender<Job<A>>,
+}
+
+impl<A> WorkerPool<A> where A: Send + 'static {
+	pub(crate) fn new(worker_count: usize) -> Self {
+		let (sender, receiver) = bounded::<Job<A>>(worker_count * 4);
+		for _ in 0..worker_count {
+			let receiver = receiver.clone();
+			thread::spawn(move || {
+				while let Ok(job) = receiver.recv() {
+					if !job.context.cancellation.is_cancelled() {
+						let mut handler = job.handler.handler.lock().unwrap();
+						// Run behind `catch_unwind` so a panicking handler only
+						// fails this one dispatch. Without it, the panic would
+						// unwind straight through this thread while `handler`
+						// (the MutexGuard) is still alive, poisoning the
+						// handler's lock — and the *next* message to the same
+						// handler could well be a plain, non-blocking one that
+						// `dispatch_to_handler` runs inline on the looper
+						// thread via the same lock, taking the whole looper
+						// down with it instead of just this worker.
+						let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+							handler.message_received(&job.context, &job.message);
+						}));
+						drop(handler);
+						if let Err(panic) = outcome {
+							println!("blocking dispatch: handler panicked: {}", panic_message(&panic));
+						}
+					}
+					job.handler.blocking_in_flight.fetch_sub(1, AtomicOrdering::Relaxed);
+				}
+			});
+		}
+		WorkerPool { sender }
+	}
+
+	// Queue `job` on the pool, marking its handler as having one more
+	// blocking dispatch in flight before handing it off.
+	fn submit(&self, job: Job<A>) {
+		job.handler.blocking_in_flight.fetch_add(1, AtomicOrdering::Relaxed);
+		if let Err(e) = self.sender.send(job) {
+			println!("blocking dispatch: worker pool is gone: {:?}", e);
+		}
+	}
+}
+
+// Best-effort extraction of a human-readable message from a `catch_unwind`
+// payload; `panic!` usually carries a `&str` or `String`, but the type is
+// otherwise unconstrained.
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> &str {
+	if let Some(message) = payload.downcast_ref::<&str>() {
+		message
+	} else if let Some(message) = payload.downcast_ref::<String>() {
+		message
+	} else {
+		"unknown panic payload"
+	}
+}
+
+// Private control messages used to drive the message runner subsystem from
+// `Context`, across to the looper thread that actually owns the schedule.
+// These never reach `Handler::message_received`.
+const B_MESSAGE_RUNNER_SCHEDULE: u32 = haiku_constant!('_','M','R','S');
+const B_MESSAGE_RUNNER_CANCEL: u32 = haiku_constant!('_','M','R','C');
+
+/// Opaque handle identifying an outstanding scheduled message, as created by
+/// [`Context::send_message_after`] or [`Context::send_message_repeating`].
+///
+/// A token is only meaningful to the looper that issued it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MessageRunnerToken(u64);
+
+/// A handle to a scheduled, possibly repeating, message, modeled after
+/// Haiku's `BMessageRunner`.
+///
+/// Dropping a `MessageRunner` does *not* cancel it; call [`MessageRunner::cancel`]
+/// explicitly, mirroring `BMessageRunner`'s own lifetime rules.
+pub struct MessageRunner {
+	token: MessageRunnerToken,
+	looper_messenger: Messenger,
+}
+
+impl MessageRunner {
+	/// The token that identifies this runner on its owning looper.
+	pub fn token(&self) -> MessageRunnerToken {
+		self.token
+	}
+
+	/// Cancel this runner, so it no longer fires. Cancelling a runner that
+	/// already fired its last repeat is a no-op.
+	pub fn cancel(&self) -> Result<()> {
+		let mut control = Message::new(B_MESSAGE_RUNNER_CANCEL);
+		control.add_data("token", &self.token.0);
+		self.looper_messenger.send_and_ask_reply(control, &self.looper_messenger)
+	}
+}
+
+/// Identifies a [`Handler`] registered on a [`Looper`] via [`Looper::add_handler`].
+///
+/// A `Messenger` targeting a specific handler (see [`Looper::handler_messenger`])
+/// stamps its token into every `Message` it sends, so `looper_task` can route
+/// the message straight to that handler instead of the looper's default state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HandlerToken(u64);
+
+/// Identifies a single outstanding request made with [`Context::send_for_reply`].
+///
+/// Only unique within the looper that issued it: the id is stamped into the
+/// outgoing message's `request_id` field, and [`Context::reply`] echoes it
+/// back into a separate `in_reply_to` field, so the two can be matched up
+/// without a hand-rolled `what`-code protocol and without a fresh request
+/// from some other looper ever being confused for one of our own replies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RequestId(u64);
+
+/// A handle to a request sent with [`Context::send_for_reply`], resolved once
+/// the looper observes a reply carrying the matching [`RequestId`].
+///
+/// Dropping a `PendingReply` before it resolves cancels it, so a peer that
+/// never replies (or has gone away) doesn't leak the pending entry.
+pub struct PendingReply {
+	id: RequestId,
+	receiver: mpsc::Receiver<Message>,
+	pending_requests: Arc<Mutex<HashMap<RequestId, mpsc::Sender<Message>>>>,
+}
+
+impl PendingReply {
+	pub fn id(&self) -> RequestId {
+		self.id
+	}
+
+	/// Block until the matching reply arrives.
+	pub fn recv(&self) -> Result<Message> {
+		self.receiver.recv().map_err(|_| HaikuError::new(ErrorKind::InvalidData, "reply channel closed without a reply"))
+	}
+
+	/// Block until the matching reply arrives, or `timeout` elapses.
+	pub fn recv_timeout(&self, timeout: Duration) -> Result<Message> {
+		self.receiver.recv_timeout(timeout).map_err(|_| HaikuError::new(ErrorKind::TimedOut, "no reply received for request"))
+	}
+
+	/// Give up on this request, so a late reply is dropped instead of
+	/// resolving anything.
+	pub fn cancel(&self) {
+		self.pending_requests.lock().unwrap().remove(&self.id);
+	}
+}
+
+impl Drop for PendingReply {
+	fn drop(&mut self) {
+		self.cancel();
+	}
+}
+
+// An entry in a `Looper`'s schedule. Ordered ascending by `fire`, so that the
+// heap (a max-heap by default) can be used as a min-heap by reversing `Ord`.
+struct ScheduledMessage {
+	token: MessageRunnerToken,
+	fire: Instant,
+	interval: Option<Duration>,
+	remaining: Option<u32>,
+	target: Messenger,
+	message: Message,
+}
+
+impl PartialEq for ScheduledMessage {
+	fn eq(&self, other: &Self) -> bool {
+		self.fire == other.fire
+	}
+}
+
+impl Eq for ScheduledMessage {}
+
+impl PartialOrd for ScheduledMessage {
+	fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+impl Ord for ScheduledMessage {
+	fn cmp(&self, other: &Self) -> Ordering {
+		// Reversed, so that `BinaryHeap::peek` returns the soonest entry.
+		other.fire.cmp(&self.fire)
+	}
 }
 
 pub struct Looper<A> where A: Send + 'static {
 	pub(crate) name: String,
 	pub(crate) port: Port,
-	pub(crate) message_queue: VecDeque<Message>,
-//	pub(crate) handlers: Vec<Box<dyn Handler<A> + Send>>,
+	pub(crate) message_queue: VecDeque<(Message, Instant)>,
+	pub(crate) handlers: HashMap<HandlerToken, Arc<HandlerSlot<A>>>,
+	pub(crate) next_handler_token: u64,
 	pub(crate) context: Context<A>,
-	pub(crate) state: Box<dyn Handler<A> + Send>,
-	pub(crate) terminating: bool
+	pub(crate) state: Arc<HandlerSlot<A>>,
+	pub(crate) terminating: bool,
+	pub(crate) scheduled: BinaryHeap<ScheduledMessage>,
+	pub(crate) next_runner_token: Arc<AtomicU64>,
+	pub(crate) workers: Arc<WorkerPool<A>>,
 }
 
-impl<A> Looper<A> where A: Send + 'static {	
+impl<A> Looper<A> where A: Send + 'static {
 	pub fn name(&self) -> &str {
 		&self.name
 	}
-	
+
 	pub fn get_messenger(&self) -> Messenger {
 		Messenger::from_port(&self.port).unwrap()
 	}
-	
+
+	/// Register `handler` with this looper and return a token that a
+	/// `Messenger` (see [`Looper::handler_messenger`]) can use to address it
+	/// directly, independent of the looper's default state.
+	pub fn add_handler(&mut self, handler: Box<dyn Handler<A> + Send>) -> HandlerToken {
+		let token = HandlerToken(self.next_handler_token);
+		self.next_handler_token += 1;
+		self.handlers.insert(token, Arc::new(HandlerSlot::new(handler)));
+		token
+	}
+
+	/// Unregister a handler previously added with [`Looper::add_handler`],
+	/// returning it if it was still present and isn't currently running on
+	/// the blocking worker pool.
+	pub fn remove_handler(&mut self, token: HandlerToken) -> Option<Box<dyn Handler<A> + Send>> {
+		self.handlers.remove(&token)
+			.and_then(|slot| Arc::try_unwrap(slot).ok())
+			.map(|slot| slot.handler.into_inner().unwrap())
+	}
+
+	/// A `Messenger` that addresses the handler identified by `token` on this
+	/// looper, rather than the looper's default state.
+	pub fn handler_messenger(&self, token: HandlerToken) -> Messenger {
+		Messenger::from_port_and_handler(&self.port, token.0).unwrap()
+	}
+
 	pub fn run(mut self) -> Result<()> {
 		let child = thread::spawn(move || {
-			println!("[{}] Running looper", self.name());
 			self.looper_task();
 		});
 		Ok(())
@@ -46,14 +331,16 @@ impl<A> Looper<A> where A: Send + 'static {
 
 	pub(crate) fn looper_task(&mut self) {
 		loop {
-			println!("[{}] outer loop", self.name());
-
-			// Try to read the first message from the port
-			// This will block until there is a message
-			match self.read_message_from_port(INFINITE_TIMEOUT) {
-				Ok(message) => self.message_queue.push_back(message),
+			// Try to read the first message from the port. This blocks until
+			// there is a message, or until the soonest scheduled message is
+			// due, whichever comes first.
+			match self.read_message_from_port(self.next_timeout()) {
+				Ok(message) => self.enqueue(message),
+				Err(ref e) if e.kind() == ErrorKind::TimedOut => {
+					self.fire_due_schedules();
+				}
 				Err(e) => {
-					println!("[{}] Error getting message: {:?}", self.name(), e); 
+					println!("[{}] Error getting message: {:?}", self.name(), e);
 					continue;
 				}
 			}
@@ -63,9 +350,9 @@ impl<A> Looper<A> where A: Send + 'static {
 			for _ in 0..message_count {
 				// use timeout of 0 because we know there is a next message
 				match self.read_message_from_port(Duration::new(0,0)) {
-					Ok(message) => self.message_queue.push_back(message),
+					Ok(message) => self.enqueue(message),
 					Err(e) => {
-						println!("Error getting message: {:?}", e); 
+						println!("[{}] Error getting message: {:?}", self.name(), e);
 						break;
 					}
 				}
@@ -75,49 +362,219 @@ impl<A> Looper<A> where A: Send + 'static {
 			// queue, this is the inner loop
 			let mut dispatch_next_message = true;
 			while dispatch_next_message && ! self.terminating {
-				let message = self.message_queue.pop_front();
-				
-				if message.is_none() {
+				let next = self.message_queue.pop_front();
+
+				if next.is_none() {
 					dispatch_next_message = false;
 				} else {
-					let message = message.unwrap();
-					println!("[{}] Handling message {:?}", self.name(), message);
-					
+					let (message, read_at) = next.unwrap();
+
 					match message.what() {
-						B_QUIT_REQUESTED => {},
-						QUIT => { self.terminating = true; },
-						_ => {
-							// Todo: support handler tokens and targeting
-					
-		//					for handler in self.handlers.iter_mut() {
-		//						handler.message_received(&self.context, &message);
-		//					}
-							self.state.message_received(&self.context, &message);
-						}
+						B_QUIT_REQUESTED => self.context.cancellation.cancel(),
+						QUIT => {
+							self.terminating = true;
+							self.context.cancellation.cancel();
+						},
+						B_MESSAGE_RUNNER_SCHEDULE => self.handle_schedule_request(&message),
+						B_MESSAGE_RUNNER_CANCEL => self.handle_cancel_request(&message),
+						// Pending requests are drained before normal routing, so a
+						// reply never reaches a handler as if it were a fresh message.
+						_ if self.try_resolve_pending_request(&message) => {},
+						_ => self.dispatch_to_handler(&message, read_at),
 					}
 				}
 
 				if self.terminating {
 					break;
 				}
-				
+
 				match self.port.get_count() {
 					Ok(count) => {
 						if count > 0 {
 							dispatch_next_message = false;
 						}
 					},
-					Err(e) => println!("Error getting the port count: {:?}", e)
+					Err(e) => println!("[{}] Error getting the port count: {:?}", self.name(), e)
 				}
 			}
 			if self.terminating {
-				println!("[{}] terminating looper", self.name());
 				break;
 			}
-			println!("[{}] at the end of the outer loop", self.name());
 		}
 	}
 
+	// Push a freshly read message onto the queue, stamping its arrival time,
+	// and trace it as read if anyone is observing this application.
+	fn enqueue(&mut self, message: Message) {
+		if self.context.inspector.has_observers() {
+			self.context.inspector.record(
+				TracePoint::Read,
+				&self.name,
+				&message,
+				message.get_return_address(),
+				message.target_handler_token(),
+				self.message_queue.len(),
+				Instant::now(),
+				None,
+			);
+		}
+		self.message_queue.push_back((message, Instant::now()));
+	}
+
+	// The timeout to hand to `read_message_from_port`: either the time left
+	// until the soonest scheduled message is due, zero if it is already due,
+	// or `INFINITE_TIMEOUT` if nothing is scheduled.
+	fn next_timeout(&self) -> Duration {
+		match self.scheduled.peek() {
+			Some(next) => {
+				let now = Instant::now();
+				if next.fire <= now {
+					Duration::new(0, 0)
+				} else {
+					next.fire - now
+				}
+			}
+			None => INFINITE_TIMEOUT,
+		}
+	}
+
+	// Pop every scheduled message whose fire time has passed, post it to its
+	// target, and re-insert repeating ones with their next fire time.
+	fn fire_due_schedules(&mut self) {
+		let now = Instant::now();
+		while let Some(next) = self.scheduled.peek() {
+			if next.fire > now {
+				break;
+			}
+			let mut due = self.scheduled.pop().unwrap();
+			let is_self = due.target.get_port() == self.port;
+			if is_self {
+				self.enqueue(due.message.clone());
+			} else if let Err(e) = due.target.send_and_ask_reply(due.message.clone(), &self.context.looper_messenger) {
+				println!("[{}] Error delivering scheduled message: {:?}", self.name(), e);
+			}
+
+			let keep_going = match due.remaining {
+				Some(ref mut remaining) => {
+					*remaining -= 1;
+					*remaining > 0
+				}
+				None => true,
+			};
+			if keep_going {
+				if let Some(interval) = due.interval {
+					due.fire += interval;
+					self.scheduled.push(due);
+				}
+			}
+		}
+	}
+
+	// If `message` carries the `RequestId` of one of our own still-pending
+	// requests in its `in_reply_to` field, hand it to the waiting
+	// `PendingReply` and report that it was consumed; otherwise leave it for
+	// normal dispatch. This only ever matches an actual reply sent through
+	// `Context::reply` — a fresh request stamps its correlation id into
+	// `request_id` instead, so it can never be mistaken for one of our own
+	// pending replies even if the numeric ids happen to coincide.
+	fn try_resolve_pending_request(&self, message: &Message) -> bool {
+		let id = match message.find_data::<u64>("in_reply_to", 0) {
+			Ok(id) => RequestId(id),
+			Err(_) => return false,
+		};
+		match self.context.pending_requests.lock().unwrap().remove(&id) {
+			Some(sender) => { sender.send(message.clone()).ok(); true }
+			None => false,
+		}
+	}
+
+	// Route a dispatched message to the handler addressed by its target
+	// token, if any, falling through to the looper's default state when the
+	// message carries no token or addresses one that is no longer registered.
+	// A handler that declares the message blocking (or that already has a
+	// blocking dispatch in flight) runs on the worker pool instead, so this
+	// looper can carry on draining its port meanwhile.
+	fn dispatch_to_handler(&mut self, message: &Message, read_at: Instant) {
+		let target_handler = message.target_handler_token();
+
+		if self.context.inspector.has_observers() {
+			self.context.inspector.record(
+				TracePoint::Dispatch,
+				&self.name,
+				message,
+				message.get_return_address(),
+				target_handler,
+				self.message_queue.len(),
+				read_at,
+				Some(Instant::now()),
+			);
+		}
+
+		let handler = match target_handler {
+			Some(token) => match self.handlers.get(&token) {
+				Some(handler) => handler.clone(),
+				// The addressed handler is gone; fall through to default state.
+				None => self.state.clone(),
+			},
+			None => self.state.clone(),
+		};
+
+		// Each dispatch gets its own `Context`, so that a blocking dispatch on
+		// the worker pool doesn't race another dispatch over which request
+		// `Context::reply` should echo.
+		let mut message_context = self.context.clone();
+		message_context.current_request_id = message.find_data::<u64>("request_id", 0).ok().map(RequestId);
+
+		// A handler with a blocking dispatch already in flight gets this one
+		// queued on the pool too, without even asking `is_blocking` — doing so
+		// would mean locking the handler here on the looper thread, exactly
+		// the lock a worker may be holding for the duration of its call.
+		if handler.blocking_in_flight.load(AtomicOrdering::Relaxed) > 0 {
+			self.workers.submit(Job { handler, context: message_context, message: message.clone() });
+			return;
+		}
+
+		let is_blocking = handler.handler.lock().unwrap().is_blocking(message);
+		if is_blocking {
+			self.workers.submit(Job { handler, context: message_context, message: message.clone() });
+		} else {
+			handler.handler.lock().unwrap().message_received(&message_context, message);
+		}
+	}
+
+	fn handle_schedule_request(&mut self, message: &Message) {
+		let token = MessageRunnerToken(message.find_data::<u64>("token", 0).unwrap());
+		let delay = Duration::from_nanos(message.find_data::<u64>("delay_nanos", 0).unwrap());
+		let interval = message.find_data::<u64>("interval_nanos", 0).ok()
+			.map(Duration::from_nanos)
+			.filter(|d| *d > Duration::new(0, 0));
+		// A `count` of zero would otherwise fire once (the remaining-check only
+		// runs after firing) and then underflow on its first decrement, so treat
+		// it the same as "never fires": drop the schedule entirely.
+		let remaining = message.find_data::<i64>("count", 0).ok()
+			.filter(|count| *count >= 0)
+			.map(|count| count as u32);
+		if remaining == Some(0) {
+			return;
+		}
+		let target = message.find_data::<Messenger>("target", 0).unwrap();
+		let payload = message.find_data::<Message>("message", 0).unwrap();
+
+		self.scheduled.push(ScheduledMessage {
+			token,
+			fire: Instant::now() + delay,
+			interval,
+			remaining,
+			target,
+			message: payload,
+		});
+	}
+
+	fn handle_cancel_request(&mut self, message: &Message) {
+		let token = MessageRunnerToken(message.find_data::<u64>("token", 0).unwrap());
+		self.scheduled.retain(|scheduled| scheduled.token != token);
+	}
+
 	fn read_message_from_port(&self, timeout: Duration) -> Result<Message> {
 		// TODO: handle B_INTERRUPTED?
 		let (type_code, buffer) = self.port.try_read(timeout)?;
@@ -129,3 +586,74 @@ impl<A> Looper<A> where A: Send + 'static {
 		}
 	}
 }
+
+impl<A> Context<A> where A: Send + 'static {
+	/// Schedule `message` to be posted to `target` once, after `delay` has
+	/// elapsed.
+	pub fn send_message_after(&self, target: Messenger, message: Message, delay: Duration) -> Result<MessageRunner> {
+		self.schedule_message(target, message, delay, None, Some(1))
+	}
+
+	/// Schedule `message` to be posted to `target` every `interval`, starting
+	/// after one `interval` has elapsed. Pass `count` to limit the number of
+	/// repeats, or `None` to repeat forever until cancelled. `Some(0)` never
+	/// fires at all.
+	pub fn send_message_repeating(&self, target: Messenger, message: Message, interval: Duration, count: Option<u32>) -> Result<MessageRunner> {
+		self.schedule_message(target, message, interval, Some(interval), count)
+	}
+
+	fn schedule_message(&self, target: Messenger, message: Message, delay: Duration, interval: Option<Duration>, count: Option<u32>) -> Result<MessageRunner> {
+		let token = MessageRunnerToken(self.next_runner_token.fetch_add(1, AtomicOrdering::Relaxed));
+
+		let mut control = Message::new(B_MESSAGE_RUNNER_SCHEDULE);
+		control.add_data("token", &token.0);
+		control.add_data("delay_nanos", &(delay.as_secs() * 1_000_000_000 + delay.subsec_nanos() as u64));
+		if let Some(interval) = interval {
+			control.add_data("interval_nanos", &(interval.as_secs() * 1_000_000_000 + interval.subsec_nanos() as u64));
+		}
+		control.add_data("count", &count.map(|c| c as i64).unwrap_or(-1));
+		control.add_data("target", &target);
+		control.add_data("message", &message);
+
+		self.looper_messenger.send_and_ask_reply(control, &self.looper_messenger)?;
+
+		Ok(MessageRunner { token, looper_messenger: self.looper_messenger.clone() })
+	}
+
+	/// Send `message` to `target` and return a [`PendingReply`] that resolves
+	/// once this looper observes a reply carrying the matching [`RequestId`].
+	///
+	/// The request id is stamped into `message`'s `request_id` field before
+	/// it is sent; a handler replying with [`Context::reply`] echoes it back
+	/// in a separate `in_reply_to` field, so there is no need for a
+	/// hand-rolled `what`-code protocol to correlate the two. Keeping the two
+	/// fields distinct matters: ids are only unique within the looper that
+	/// issued them, so a fresh request from some other looper can carry the
+	/// same numeric `request_id` we ourselves are waiting on without being
+	/// mistaken for our reply.
+	pub fn send_for_reply(&self, target: Messenger, mut message: Message) -> Result<PendingReply> {
+		let id = RequestId(self.next_request_id.fetch_add(1, AtomicOrdering::Relaxed));
+		message.add_data("request_id", &id.0);
+
+		let (sender, receiver) = mpsc::channel();
+		self.pending_requests.lock().unwrap().insert(id, sender);
+
+		if let Err(e) = target.send_and_ask_reply(message, &self.looper_messenger) {
+			self.pending_requests.lock().unwrap().remove(&id);
+			return Err(e);
+		}
+
+		Ok(PendingReply { id, receiver, pending_requests: self.pending_requests.clone() })
+	}
+
+	/// Send `message` to `target` as a reply. If the handler is currently
+	/// responding to a message received through [`Context::send_for_reply`],
+	/// its `RequestId` is echoed into `message`'s `in_reply_to` field first,
+	/// so the original sender's `PendingReply` resolves.
+	pub fn reply(&self, target: &Messenger, mut message: Message) -> Result<()> {
+		if let Some(id) = self.current_request_id {
+			message.add_data("in_reply_to", &id.0);
+		}
+		target.send_and_ask_reply(message, &self.looper_messenger)
+	}
+}
@@ -0,0 +1,302 @@
+//
+// Copyright 2019, Niels Sascha Reedijk <niels.reedijk@gmail.com>
+// All rights reserved. Distributed under the terms of the MIT License.
+//
+
+//! A relay that lets a `Messenger` deliver `Message`s to an `Application`
+//! running on another host.
+//!
+//! Each side of a link runs a [`Relay`], which accepts inbound TCP
+//! connections and owns the outbound connections it opens to peers. Every
+//! frame on the wire is:
+//!
+//! ```text
+//! +----------------+----------------+--------------------------+
+//! | length: u32 BE | type_code: u32 | flattened Message payload |
+//! +----------------+----------------+--------------------------+
+//! ```
+//!
+//! `length` covers `type_code` plus the payload that follows it. Before any
+//! `Message` frames cross the wire, both ends exchange a `Handshake` frame
+//! identifying their team.
+//!
+//! [`Relay::connect`] keeps every [`RemoteMessenger`] it hands out, keyed by
+//! the [`RemoteAddress`] it addresses, so [`Relay::messenger_for`] can find
+//! an already-open connection instead of dialing a new one. Note that this
+//! only covers connections *this* relay initiated: a message forwarded in
+//! from an inbound connection doesn't carry enough information on its own to
+//! resolve a reply back across that same link, so replying to a remote peer
+//! today means the handler holding (or re-deriving) the peer's address calls
+//! [`Relay::connect`] (or [`Relay::messenger_for`]) itself.
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use ::app::Message;
+use ::kernel::ports::Port;
+use ::support::{ErrorKind, Flattenable, HaikuError, Result};
+
+const FRAME_LENGTH_SIZE: usize = 4;
+const TYPE_CODE_SIZE: usize = 4;
+
+// A generous ceiling on a single frame's declared length, so a peer can't
+// force an arbitrarily large allocation before we've even checked whether the
+// bytes behind it ever arrive.
+const MAX_FRAME_LENGTH: usize = 64 * 1024 * 1024;
+
+/// A `team_id`/port pair identifying a `Messenger`'s target on a remote host,
+/// as exchanged during the relay handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RemoteAddress {
+	pub team: i32,
+	pub port: i32,
+}
+
+/// A handle to a `Messenger` living on the other end of a [`Relay`] link.
+///
+/// Sending through a `RemoteMessenger` flattens the `Message` and writes it
+/// as a frame on the relay's outbound connection; there is no local `Port`
+/// to deliver to directly.
+#[derive(Clone)]
+pub struct RemoteMessenger {
+	address: RemoteAddress,
+	connection: Arc<Mutex<TcpStream>>,
+}
+
+impl RemoteMessenger {
+	/// The remote team/port this messenger addresses.
+	pub fn address(&self) -> RemoteAddress {
+		self.address
+	}
+
+	/// Flatten `message` and write it as a frame to the peer.
+	pub fn send(&self, message: Message) -> Result<()> {
+		let mut connection = self.connection.lock().unwrap();
+		write_frame(&mut connection, &message)
+	}
+}
+
+// The handshake frame exchanged once, right after a connection is
+// established: each side sends its team, the connecting side names the
+// exposed port on the accepting side that its frames are for, and the
+// accepting side sends `port: 0`.
+struct Handshake {
+	team: i32,
+	port: i32,
+}
+
+impl Handshake {
+	fn write(&self, stream: &mut TcpStream) -> Result<()> {
+		let mut buffer = Vec::with_capacity(8);
+		buffer.extend_from_slice(&self.team.to_be_bytes());
+		buffer.extend_from_slice(&self.port.to_be_bytes());
+		stream.write_all(&buffer).map_err(|e| HaikuError::new(ErrorKind::Other, &e.to_string()))
+	}
+
+	fn read(stream: &mut TcpStream) -> Result<Self> {
+		let mut buffer = [0u8; 8];
+		stream.read_exact(&mut buffer).map_err(|e| HaikuError::new(ErrorKind::Other, &e.to_string()))?;
+		Ok(Handshake {
+			team: i32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]),
+			port: i32::from_be_bytes([buffer[4], buffer[5], buffer[6], buffer[7]]),
+		})
+	}
+}
+
+fn write_frame(stream: &mut TcpStream, message: &Message) -> Result<()> {
+	let payload = message.flatten()?;
+	let mut frame = Vec::with_capacity(FRAME_LENGTH_SIZE + TYPE_CODE_SIZE + payload.len());
+	let length = (TYPE_CODE_SIZE + payload.len()) as u32;
+	frame.extend_from_slice(&length.to_be_bytes());
+	frame.extend_from_slice(&Message::type_code().to_be_bytes());
+	frame.extend_from_slice(&payload);
+	stream.write_all(&frame).map_err(|e| HaikuError::new(ErrorKind::Other, &e.to_string()))
+}
+
+fn read_frame(stream: &mut TcpStream) -> Result<Message> {
+	let mut length_buffer = [0u8; FRAME_LENGTH_SIZE];
+	stream.read_exact(&mut length_buffer).map_err(|e| HaikuError::new(ErrorKind::Other, &e.to_string()))?;
+	let length = u32::from_be_bytes(length_buffer) as usize;
+
+	if length < TYPE_CODE_SIZE {
+		return Err(HaikuError::new(ErrorKind::InvalidData, "relay frame is too short to contain a type code"));
+	}
+	if length > MAX_FRAME_LENGTH {
+		return Err(HaikuError::new(ErrorKind::InvalidData, "relay frame exceeds the maximum allowed length"));
+	}
+
+	let mut body = vec![0u8; length];
+	stream.read_exact(&mut body).map_err(|e| HaikuError::new(ErrorKind::Other, &e.to_string()))?;
+
+	let type_code = u32::from_be_bytes([body[0], body[1], body[2], body[3]]);
+	if type_code != Message::type_code() {
+		return Err(HaikuError::new(ErrorKind::InvalidData, "relay frame does not contain a Message"));
+	}
+	Message::unflatten(&body[TYPE_CODE_SIZE..])
+}
+
+/// Bridges local `Port`s to remote peers over TCP.
+///
+/// A `Relay` accepts inbound connections on behalf of `local_team` and posts
+/// every `Message` it reads to whichever exposed local `Port` the
+/// connection's handshake addressed; outbound, [`Relay::connect`] hands back
+/// a [`RemoteMessenger`] that sends to the peer.
+pub struct Relay {
+	local_team: i32,
+	exposed_ports: Arc<Mutex<Vec<Port>>>,
+	connections: Arc<Mutex<HashMap<RemoteAddress, RemoteMessenger>>>,
+}
+
+impl Relay {
+	/// Start listening on `address` for inbound connections. Each accepted
+	/// connection gets its own reader thread that delivers frames to the
+	/// exposed local `Port` the handshake addressed.
+	pub fn listen<T: ToSocketAddrs>(local_team: i32, address: T) -> Result<Self> {
+		let listener = TcpListener::bind(address).map_err(|e| HaikuError::new(ErrorKind::Other, &e.to_string()))?;
+		let exposed_ports = Arc::new(Mutex::new(Vec::new()));
+
+		let accept_ports = exposed_ports.clone();
+		thread::spawn(move || {
+			for connection in listener.incoming() {
+				match connection {
+					Ok(stream) => Relay::accept(local_team, stream, accept_ports.clone()),
+					Err(e) => println!("relay: error accepting connection: {:?}", e),
+				}
+			}
+		});
+
+		Ok(Relay { local_team, exposed_ports, connections: Arc::new(Mutex::new(HashMap::new())) })
+	}
+
+	/// Make `port` reachable to remote peers that connect to this relay and
+	/// address it by its port id.
+	pub fn expose(&self, port: Port) {
+		self.exposed_ports.lock().unwrap().push(port);
+	}
+
+	/// An already-open [`RemoteMessenger`] addressing `address`, if
+	/// [`Relay::connect`] has dialed it before.
+	pub fn messenger_for(&self, address: RemoteAddress) -> Option<RemoteMessenger> {
+		self.connections.lock().unwrap().get(&address).cloned()
+	}
+
+	// Runs on its own thread for the lifetime of one inbound connection:
+	// handshake once, then loop reading frames and forwarding each to the
+	// exposed port the peer addressed.
+	fn accept(local_team: i32, mut stream: TcpStream, exposed_ports: Arc<Mutex<Vec<Port>>>) {
+		thread::spawn(move || {
+			if let Err(e) = (Handshake { team: local_team, port: 0 }).write(&mut stream) {
+				println!("relay: handshake write failed: {:?}", e);
+				return;
+			}
+			let peer = match Handshake::read(&mut stream) {
+				Ok(handshake) => handshake,
+				Err(e) => { println!("relay: handshake read failed: {:?}", e); return; }
+			};
+
+			loop {
+				match read_frame(&mut stream) {
+					Ok(message) => {
+						let ports = exposed_ports.lock().unwrap();
+						match ports.iter().find(|port| port.id() == peer.port) {
+							Some(port) => if let Err(e) = port.send(message) {
+								println!("relay: error delivering message: {:?}", e);
+							},
+							None => println!("relay: no exposed port {} for inbound message", peer.port),
+						}
+					}
+					Err(e) => {
+						println!("relay: connection closed: {:?}", e);
+						break;
+					}
+				}
+			}
+		});
+	}
+
+	/// Connect to a peer relay listening at `address`, exchange handshakes,
+	/// and return a [`RemoteMessenger`] addressing `remote_port` on it.
+	///
+	/// The returned messenger is also recorded under its [`RemoteAddress`],
+	/// so a later [`Relay::messenger_for`] call can find it again without
+	/// dialing a second connection.
+	pub fn connect<T: ToSocketAddrs>(&self, address: T, remote_port: i32) -> Result<RemoteMessenger> {
+		let mut stream = TcpStream::connect(address).map_err(|e| HaikuError::new(ErrorKind::Other, &e.to_string()))?;
+		(Handshake { team: self.local_team, port: remote_port }).write(&mut stream)?;
+		let peer = Handshake::read(&mut stream)?;
+
+		// The accepting side always echoes back `port: 0` (see `Relay::accept`;
+		// it has no port of its own to report, only the ports it exposes), so
+		// the port we address the peer on is the one we asked for, not the one
+		// it echoed.
+		let address = RemoteAddress { team: peer.team, port: remote_port };
+		let messenger = RemoteMessenger { address, connection: Arc::new(Mutex::new(stream)) };
+		self.connections.lock().unwrap().insert(address, messenger.clone());
+		Ok(messenger)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::net::TcpListener;
+
+	fn loopback_pair() -> (TcpStream, TcpStream) {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+		let client = TcpStream::connect(addr).unwrap();
+		let (server, _) = listener.accept().unwrap();
+		(client, server)
+	}
+
+	#[test]
+	fn write_frame_round_trips_through_read_frame() {
+		let (mut client, mut server) = loopback_pair();
+		let message = Message::new(haiku_constant!('T','E','S','T'));
+
+		write_frame(&mut client, &message).unwrap();
+		let received = read_frame(&mut server).unwrap();
+
+		assert_eq!(received.what(), message.what());
+	}
+
+	#[test]
+	fn read_frame_rejects_a_length_too_short_for_a_type_code() {
+		let (mut client, mut server) = loopback_pair();
+
+		client.write_all(&3u32.to_be_bytes()).unwrap();
+		client.write_all(&[0u8; 3]).unwrap();
+
+		assert!(read_frame(&mut server).is_err());
+	}
+
+	#[test]
+	fn read_frame_rejects_a_length_over_the_maximum() {
+		let (mut client, mut server) = loopback_pair();
+
+		client.write_all(&((MAX_FRAME_LENGTH + 1) as u32).to_be_bytes()).unwrap();
+
+		assert!(read_frame(&mut server).is_err());
+	}
+
+	#[test]
+	fn connect_addresses_the_requested_port_not_the_echoed_one() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+
+		thread::spawn(move || {
+			let (mut stream, _) = listener.accept().unwrap();
+			Handshake { team: 42, port: 0 }.write(&mut stream).unwrap();
+			Handshake::read(&mut stream).unwrap();
+		});
+
+		let relay = Relay { local_team: 1, exposed_ports: Arc::new(Mutex::new(Vec::new())), connections: Arc::new(Mutex::new(HashMap::new())) };
+		let messenger = relay.connect(addr, 7).unwrap();
+
+		assert_eq!(messenger.address(), RemoteAddress { team: 42, port: 7 });
+		assert_eq!(relay.messenger_for(messenger.address()).unwrap().address(), messenger.address());
+	}
+}